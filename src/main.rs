@@ -11,25 +11,36 @@ extern crate thread_id;
 extern crate rustbox;
 extern crate number_prefix;
 extern crate clap;
+extern crate flate2;
+extern crate bzip2;
+extern crate xz2;
+extern crate tar;
+extern crate sha2;
 
 mod util;
 
 use std::path::{Path, PathBuf};
 use std::{io, thread};
 use std::io::prelude::*;
-use std::fs::File;
-use std::sync::Arc;
-use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::fs::{self, File, OpenOptions};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use hyper::client::IntoUrl;
 use hyper::{Client, Url};
-use hyper::header::ContentLength;
+use hyper::header::{AcceptRanges, ByteRangeSpec, ContentLength, Range, RangeUnit};
+use hyper::status::StatusCode;
 use scoped_threadpool::Pool;
 use crossbeam::sync::MsQueue;
 use rustbox::{RustBox, Color, Key};
 use number_prefix::{decimal_prefix, Standalone, Prefixed};
 use clap::{App, Arg};
+use flate2::read::GzDecoder;
+use bzip2::read::BzDecoder;
+use xz2::read::XzDecoder;
+use sha2::{Digest, Sha256};
 
 use util::DurationExt;
 
@@ -40,13 +51,13 @@ pub struct Watcher<R, F> {
 
 impl<R, F> Read for Watcher<R, F>
     where R: Read,
-          F: FnMut(usize)
+          F: FnMut(&[u8])
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let result = self.inner.read(buf);
         if let Ok(n) = result {
             if n > 0 {
-                (self.f)(n);
+                (self.f)(&buf[..n]);
             }
         }
         result
@@ -65,6 +76,30 @@ trait WatchRead {
 }
 
 impl<R> WatchRead for R where R: Read {}
+
+/// Where a worker writes a downloaded body. `File` persists to disk as usual;
+/// `Buffer` keeps the bytes in memory so small resources can be post-processed
+/// by a library caller instead of always hitting disk.
+pub enum DownloadSink {
+    File(File),
+    Buffer(Vec<u8>),
+}
+
+impl Write for DownloadSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            DownloadSink::File(ref mut f) => f.write(buf),
+            DownloadSink::Buffer(ref mut v) => v.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            DownloadSink::File(ref mut f) => f.flush(),
+            DownloadSink::Buffer(ref mut v) => v.flush(),
+        }
+    }
+}
 quick_error! {
     #[derive(Debug)]
     pub enum DError {
@@ -80,16 +115,85 @@ quick_error! {
             display("HTTP error: {}", err)
             cause(err)
         }
+        StatusError(status: StatusCode) {
+            description("bad HTTP status")
+            display("bad HTTP status: {}", status)
+        }
+        ChecksumError(expected: String, actual: String) {
+            description("checksum mismatch")
+            display("checksum mismatch: expected {}, got {}", expected, actual)
+        }
     }
 }
 
 pub type DResult<T> = Result<T, DError>;
 
 #[derive(Debug)]
-struct WorkItem {
+pub struct WorkItem {
     path: PathBuf,
     url: Url,
     id: u32,
+    checksum: Option<Vec<u8>>,
+}
+
+/// Keeps the shared per-host in-flight count in sync with a running download.
+///
+/// The count is bumped when a worker is dispatched and decremented here on
+/// drop, so it is released on every exit path of the worker closure: whether
+/// `download_one` returns `Ok`, returns a `DError`, or the thread panics.
+struct HostGuard {
+    counts: Arc<Mutex<HashMap<String, usize>>>,
+    host: String,
+}
+
+impl Drop for HostGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(c) = counts.get_mut(&self.host) {
+            *c -= 1;
+        }
+    }
+}
+
+/// Token-bucket rate limiter shared across every worker. The bucket holds up to
+/// `capacity` tokens (one per byte) and refills at `rate` tokens per second; a
+/// `consume` call that outruns the refill blocks until enough tokens accrue, so
+/// aggregate throughput across all threads stays under the configured limit.
+struct RateLimiter {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            capacity: rate as f64,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    fn consume(&self, n: usize) {
+        let needed = n as f64;
+        // Reserve the tokens (possibly going negative) under the lock, then
+        // release it before sleeping so other workers can keep reading while
+        // this one waits out its share of the deficit.
+        let wait = {
+            let mut guard = self.state.lock().unwrap();
+            let (tokens, last) = *guard;
+            let tokens = (tokens + last.elapsed().seconds() * self.rate).min(self.capacity) - needed;
+            *guard = (tokens, Instant::now());
+            if tokens < 0.0 {
+                -tokens / self.rate
+            } else {
+                0.0
+            }
+        };
+        if wait > 0.0 {
+            thread::sleep(Duration::from_millis((wait * 1000.0) as u64));
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -98,11 +202,21 @@ enum Message {
         thread_id: usize,
         file_name: String,
         file_size: Option<u64>,
+        downloaded: u64,
     },
     Downloading {
         bytes_read: u64,
         thread_id: usize,
     },
+    Retrying {
+        thread_id: usize,
+        attempt: u32,
+        delay: Duration,
+    },
+    Rewind {
+        thread_id: usize,
+        bytes: u64,
+    },
     Success {
         thread_id: usize,
     },
@@ -128,6 +242,7 @@ struct Progress {
     error: Option<DError>,
     start_time: Instant,
     download_rate: f64,
+    retry: Option<(u32, Duration)>,
 }
 
 impl Progress {
@@ -138,7 +253,8 @@ impl Progress {
             progress: 0,
             error: None,
             start_time: Instant::now(),
-            download_rate: 0.0
+            download_rate: 0.0,
+            retry: None,
         }
     }
     
@@ -163,6 +279,12 @@ impl Progress {
     fn fmt_download_rate(&self) -> String {
         format!("{}/s", fmt_bytes(self.download_rate as f32))
     }
+
+    fn fmt_retry(&self, max_retries: u32) -> Option<String> {
+        self.retry.map(|(attempt, delay)| {
+            format!("retry {}/{} in {}s", attempt, max_retries, delay.as_secs())
+        })
+    }
 }
 
 struct DownloadWatcher {
@@ -171,16 +293,18 @@ struct DownloadWatcher {
     quitting: bool,
     num_files: usize,
     files_finished: usize,
+    max_retries: u32,
 }
 
 impl DownloadWatcher {
-    pub fn new(num_files: usize) -> DownloadWatcher {
+    pub fn new(num_files: usize, max_retries: u32) -> DownloadWatcher {
         DownloadWatcher {
             status_map: HashMap::new(),
             rustbox: RustBox::init(Default::default()).unwrap(),
             quitting: false,
             num_files: num_files,
             files_finished: 0,
+            max_retries: max_retries,
         }
     }
 
@@ -191,17 +315,27 @@ impl DownloadWatcher {
 
         match message {
             Message::Done => return true,
-            Message::StartFile { thread_id, file_name, file_size } => {
+            Message::StartFile { thread_id, file_name, file_size, downloaded } => {
                 self.status_map.insert(thread_id,
                                        Progress {
                                            file_name: file_name,
                                            file_size: file_size,
-                                           progress: 0,
+                                           progress: downloaded,
                                            error: None,
                                            start_time: Instant::now(),
                                            download_rate: 0.0,
+                                           retry: None,
                                        });
             }
+            Message::Retrying { thread_id, attempt, delay } => {
+                let e = self.status_map.entry(thread_id).or_insert_with(Progress::new);
+                e.retry = Some((attempt, delay));
+            }
+            Message::Rewind { thread_id, bytes } => {
+                if let Some(e) = self.status_map.get_mut(&thread_id) {
+                    e.progress = e.progress.saturating_sub(bytes);
+                }
+            }
             Message::Success { thread_id } => {
                 self.status_map.remove(&thread_id);
                 self.files_finished += 1;
@@ -213,6 +347,7 @@ impl DownloadWatcher {
                 let download_rate = new_progress as f64 / elapsed.seconds();
                 e.download_rate = download_rate;
                 e.progress = new_progress;
+                e.retry = None;
             }
             Message::Error { err, thread_id } => {
                 let e = self.status_map.entry(thread_id).or_insert_with(Progress::new);
@@ -255,7 +390,9 @@ impl DownloadWatcher {
                                Color::Black,
                                &progress.fmt_progress_percent());
             self.rustbox.print(m + 10, y, rustbox::RB_NORMAL, Color::White, Color::Black, &p);
-            self.rustbox.print(width - 15, y, rustbox::RB_NORMAL, Color::White, Color::Black, &progress.fmt_download_rate());
+            let tail = progress.fmt_retry(self.max_retries)
+                .unwrap_or_else(|| progress.fmt_download_rate());
+            self.rustbox.print(width - 20, y, rustbox::RB_NORMAL, Color::White, Color::Black, &tail);
         }
 
         self.rustbox.present();
@@ -273,84 +410,546 @@ impl DownloadWatcher {
     }
 }
 
-macro_rules! try_or_send {
-    ($expr: expr, $queue: expr) => (match $expr {
-        Ok(val) => val,
-        Err(err) => {
-            $queue.push(Message::Error { thread_id: thread_id::get(), err: From::from(err) });
-            return;
+/// Whether an error is worth retrying: network hiccups (reset, timeout,
+/// truncated body) and server-side (5xx) statuses are transient; client
+/// errors and local IO problems are not.
+fn is_transient(err: &DError) -> bool {
+    match *err {
+        DError::HyperError(_) => true,
+        DError::StatusError(status) => status.is_server_error(),
+        // A bad digest means the bytes we got are corrupt; re-fetching them is
+        // worth a try.
+        DError::ChecksumError(..) => true,
+        DError::IoError(ref e) => {
+            use std::io::ErrorKind::*;
+            match e.kind() {
+                ConnectionReset | ConnectionAborted | BrokenPipe | TimedOut |
+                UnexpectedEof | WouldBlock | Interrupted => true,
+                _ => false,
+            }
         }
-    })
+    }
 }
 
-pub fn download_in_parallel<U, P>(urls: Vec<U>, paths: &[P], thread_count: u32, timeout: u64, quiet: bool) -> DResult<()>
+/// Exponential backoff (`500ms * 2^attempt`, capped at 30s) with up to a
+/// quarter of the delay added as jitter so retrying clients don't march in
+/// lock-step.
+fn backoff_delay(attempt: u32) -> Duration {
+    const BASE_MS: u64 = 500;
+    const CAP_MS: u64 = 30_000;
+
+    let capped = BASE_MS.saturating_mul(1u64 << attempt.min(16)).min(CAP_MS);
+    let jitter_range = capped / 4;
+    let jitter = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0) % (jitter_range + 1);
+    Duration::from_millis(capped + jitter)
+}
+
+/// A compressed tarball format recognised by the `--extract` pipeline.
+enum ArchiveKind {
+    Gzip,
+    Bzip2,
+    Xz,
+}
+
+/// Classifies a URL by the compressed-tar extension it ends in, if any.
+fn archive_kind(url: &Url) -> Option<ArchiveKind> {
+    let path = url.path();
+    if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+        Some(ArchiveKind::Gzip)
+    } else if path.ends_with(".tar.bz2") {
+        Some(ArchiveKind::Bzip2)
+    } else if path.ends_with(".tar.xz") {
+        Some(ArchiveKind::Xz)
+    } else {
+        None
+    }
+}
+
+/// The directory an archive unpacks into, derived by stripping the tarball
+/// extension from the target file name (`foo.tar.gz` -> `foo`).
+fn archive_dest(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let stem = [".tar.gz", ".tgz", ".tar.bz2", ".tar.xz"]
+        .iter()
+        .find(|s| name.ends_with(**s))
+        .map_or(name, |s| &name[..name.len() - s.len()]);
+    path.with_file_name(stem)
+}
+
+/// Re-reads a completed file and checks its sha256 against `expected`. Used by
+/// the split path, where chunks are written to disk out of order and can't be
+/// hashed in a single streaming pass.
+fn verify_file(path: &Path, expected: &[u8]) -> DResult<()> {
+    let mut file = try!(File::open(path));
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = try!(file.read(&mut buf));
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let digest = hasher.finalize();
+    if digest.as_slice() != expected {
+        return Err(DError::ChecksumError(to_hex(expected), to_hex(digest.as_slice())));
+    }
+    Ok(())
+}
+
+/// Performs a single download attempt, resuming from any bytes already on disk.
+/// Emits `StartFile`/`Downloading` progress as it goes and returns an error
+/// that the caller can classify for retrying.
+fn download_one(item: &WorkItem, resume: bool, extract: bool, to_memory: bool, timeout: u64, limiter: Option<&RateLimiter>, message_queue: &MsQueue<Message>) -> DResult<Option<Vec<u8>>> {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(timeout)));
+    let path = &item.path;
+
+    // Archives are streamed straight into the decoder, and in-memory downloads
+    // never touch disk, so resuming from a partial file doesn't apply to them.
+    let kind = if extract { archive_kind(&item.url) } else { None };
+    let offset = if resume && kind.is_none() && !to_memory {
+        fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    let mut builder = client.get(item.url.clone());
+    if offset > 0 {
+        builder = builder.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+    }
+    let request = try!(builder.send());
+
+    // A resumed request whose range starts at/after the end of an
+    // already-complete file comes back as 416; report it done rather than
+    // truncating the finished file with the error body.
+    if offset > 0 && request.status == StatusCode::RangeNotSatisfiable {
+        return Ok(None);
+    }
+    // Reject anything that isn't a normal 2xx (or the 206 we asked for for a
+    // resume); otherwise a 404/403 error page would be written over the target.
+    if !request.status.is_success() && request.status != StatusCode::PartialContent {
+        return Err(DError::StatusError(request.status));
+    }
+
+    // Decompress and unpack on the fly instead of persisting the raw archive.
+    // Progress still counts bytes pulled from the network, so rates stay honest.
+    if let Some(kind) = kind {
+        let dest = archive_dest(path);
+        try!(fs::create_dir_all(&dest));
+        let length = request.headers.get::<ContentLength>().map(|c| c.0);
+        let file_name: String = path.file_name().unwrap().to_str().unwrap().into();
+        message_queue.push(Message::StartFile {
+            thread_id: thread_id::get(),
+            file_name: file_name,
+            file_size: length,
+            downloaded: 0,
+        });
+        // Hash the raw body as it feeds the decoder so a `sha256:` sidecar still
+        // verifies even though nothing is written to a single file.
+        let mut hasher = item.checksum.as_ref().map(|_| Sha256::new());
+        {
+            let reader = request.watch(|chunk| {
+                message_queue.push(Message::Downloading {
+                    bytes_read: chunk.len() as u64,
+                    thread_id: thread_id::get(),
+                });
+                if let Some(l) = limiter {
+                    l.consume(chunk.len());
+                }
+                if let Some(ref mut h) = hasher {
+                    h.update(chunk);
+                }
+            });
+            match kind {
+                ArchiveKind::Gzip => try!(tar::Archive::new(GzDecoder::new(reader)).unpack(&dest)),
+                ArchiveKind::Bzip2 => try!(tar::Archive::new(BzDecoder::new(reader)).unpack(&dest)),
+                ArchiveKind::Xz => try!(tar::Archive::new(XzDecoder::new(reader)).unpack(&dest)),
+            }
+        }
+        if let (Some(h), Some(expected)) = (hasher, item.checksum.as_ref()) {
+            let digest = h.finalize();
+            if digest.as_slice() != &expected[..] {
+                return Err(DError::ChecksumError(to_hex(expected), to_hex(digest.as_slice())));
+            }
+        }
+        return Ok(None);
+    }
+
+    let resuming = offset > 0 && request.status == StatusCode::PartialContent;
+    let start_at = if resuming { offset } else { 0 };
+    let length = request.headers.get::<ContentLength>().map(|c| c.0 + start_at);
+    let mut sink = if to_memory {
+        DownloadSink::Buffer(Vec::new())
+    } else if resuming {
+        DownloadSink::File(try!(OpenOptions::new().append(true).open(path)))
+    } else {
+        DownloadSink::File(try!(File::create(path)))
+    };
+    let file_name: String = path.file_name().unwrap().to_str().unwrap().into();
+
+    message_queue.push(Message::StartFile {
+        thread_id: thread_id::get(),
+        file_name: file_name,
+        file_size: length,
+        downloaded: start_at,
+    });
+    // Hash the body as it streams by so verification costs one pass and no
+    // extra disk reads. When resuming, seed the hasher with the bytes already
+    // on disk first so the final digest still covers the whole file.
+    let mut hasher = item.checksum.as_ref().map(|_| Sha256::new());
+    if resuming {
+        if let Some(ref mut h) = hasher {
+            let mut existing = try!(File::open(path));
+            let mut buf = [0u8; 8192];
+            loop {
+                let n = try!(existing.read(&mut buf));
+                if n == 0 {
+                    break;
+                }
+                h.update(&buf[..n]);
+            }
+        }
+    }
+    try!(io::copy(&mut request.watch(|chunk| {
+                      message_queue.push(Message::Downloading {
+                          bytes_read: chunk.len() as u64,
+                          thread_id: thread_id::get(),
+                      });
+                      if let Some(l) = limiter {
+                          l.consume(chunk.len());
+                      }
+                      if let Some(ref mut h) = hasher {
+                          h.update(chunk);
+                      }
+                  }),
+                  &mut sink));
+
+    if let (Some(h), Some(expected)) = (hasher, item.checksum.as_ref()) {
+        let digest = h.finalize();
+        if digest.as_slice() != &expected[..] {
+            // Drop the corrupt file so a retry starts from scratch.
+            if let DownloadSink::File(_) = sink {
+                let _ = fs::remove_file(path);
+            }
+            return Err(DError::ChecksumError(to_hex(expected), to_hex(digest.as_slice())));
+        }
+    }
+
+    match sink {
+        DownloadSink::Buffer(buf) => Ok(Some(buf)),
+        DownloadSink::File(_) => Ok(None),
+    }
+}
+
+/// Fetches one contiguous byte range `[start, end]` of a file and writes it to
+/// its correct offset in the pre-allocated target. Progress is reported against
+/// the owning work item's id so all chunks fold into a single progress bar.
+fn download_chunk(url: &Url, path: &Path, id: u32, start: u64, end: u64, timeout: u64, limiter: Option<&RateLimiter>, counted: &AtomicUsize, message_queue: &MsQueue<Message>) -> DResult<()> {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(timeout)));
+    let request = try!(client.get(url.clone())
+        .header(Range::Bytes(vec![ByteRangeSpec::FromTo(start, end)]))
+        .send());
+    // A range fetch must answer 206; a 200 means the server ignored the Range
+    // and is streaming the whole body, which would overrun the chunk offset.
+    if request.status != StatusCode::PartialContent {
+        return Err(DError::StatusError(request.status));
+    }
+    let mut writer = try!(OpenOptions::new().write(true).open(path));
+    try!(writer.seek(io::SeekFrom::Start(start)));
+    let key = id as usize;
+    try!(io::copy(&mut request.watch(|chunk| {
+                      counted.fetch_add(chunk.len(), Ordering::SeqCst);
+                      message_queue.push(Message::Downloading {
+                          bytes_read: chunk.len() as u64,
+                          thread_id: key,
+                      });
+                      if let Some(l) = limiter {
+                          l.consume(chunk.len());
+                      }
+                  }),
+                  &mut writer));
+    Ok(())
+}
+
+/// Downloads each file one at a time but spreads its byte ranges across
+/// `thread_count` connections, or `max_per_host` if that is lower. Falls back to the regular single-stream path
+/// for servers that don't advertise `Accept-Ranges: bytes` or hide the length.
+fn download_split(items: VecDeque<WorkItem>, thread_count: u32, timeout: u64, max_per_host: usize, max_retries: u32, resume: bool, extract: bool, limiter: Option<Arc<RateLimiter>>, message_queue: &Arc<MsQueue<Message>>) {
+    // Honour the per-host cap: even for a single file we must not open more
+    // simultaneous connections to its host than `max_per_host` allows.
+    let fan_out = ::std::cmp::max(1, ::std::cmp::min(thread_count as usize, max_per_host)) as u32;
+    let mut pool = Pool::new(fan_out);
+    for item in items {
+        let key = item.id as usize;
+
+        // Probe the server for range support and the total size.
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(timeout)));
+        let head = client.head(item.url.clone()).send();
+        let (ranges, length) = match head {
+            Ok(resp) => {
+                let ranges = match resp.headers.get::<AcceptRanges>() {
+                    Some(r) => r.0.iter().any(|u| *u == RangeUnit::Bytes),
+                    None => false,
+                };
+                (ranges, resp.headers.get::<ContentLength>().map(|c| c.0))
+            }
+            Err(_) => (false, None),
+        };
+
+        match (ranges, length) {
+            (true, Some(size)) if size > 0 => {
+                let file = match File::create(&item.path) {
+                    Ok(f) => f,
+                    Err(err) => {
+                        message_queue.push(Message::Error { thread_id: key, err: From::from(err) });
+                        continue;
+                    }
+                };
+                if let Err(err) = file.set_len(size) {
+                    message_queue.push(Message::Error { thread_id: key, err: From::from(err) });
+                    continue;
+                }
+                drop(file);
+
+                let file_name: String = item.path.file_name().unwrap().to_str().unwrap().into();
+                message_queue.push(Message::StartFile {
+                    thread_id: key,
+                    file_name: file_name,
+                    file_size: Some(size),
+                    downloaded: 0,
+                });
+
+                let chunk_size = (size + fan_out as u64 - 1) / fan_out as u64;
+                let id = item.id;
+                let url = &item.url;
+                let path = item.path.as_path();
+                let failed = Arc::new(AtomicBool::new(false));
+                pool.scoped(|scope| {
+                    for i in 0..fan_out as u64 {
+                        let start = i * chunk_size;
+                        if start >= size {
+                            break;
+                        }
+                        let end = ::std::cmp::min(start + chunk_size - 1, size - 1);
+                        let message_queue = message_queue.clone();
+                        let limiter = limiter.clone();
+                        let failed = failed.clone();
+                        scope.execute(move || {
+                            let limiter = limiter.as_ref().map(|l| &**l);
+                            let counted = AtomicUsize::new(0);
+                            let mut attempt = 0;
+                            loop {
+                                match download_chunk(url, path, id, start, end, timeout, limiter, &counted, &message_queue) {
+                                    Ok(()) => break,
+                                    Err(err) => {
+                                        if attempt < max_retries && is_transient(&err) {
+                                            let delay = backoff_delay(attempt);
+                                            // A retry re-streams this chunk from
+                                            // `start`; roll back only the bytes
+                                            // this chunk already counted so the
+                                            // shared aggregate bar stays accurate
+                                            // for sibling chunks still running.
+                                            let done = counted.swap(0, Ordering::SeqCst) as u64;
+                                            message_queue.push(Message::Rewind {
+                                                thread_id: key,
+                                                bytes: done,
+                                            });
+                                            message_queue.push(Message::Retrying {
+                                                thread_id: key,
+                                                attempt: attempt + 1,
+                                                delay: delay,
+                                            });
+                                            thread::sleep(delay);
+                                            attempt += 1;
+                                        } else {
+                                            message_queue.push(Message::Error { thread_id: key, err: err });
+                                            failed.store(true, Ordering::SeqCst);
+                                            break;
+                                        }
+                                    }
+                                }
+                            }
+                        });
+                    }
+                });
+
+                // A chunk gave up: its Error already stands, don't paper over it.
+                if failed.load(Ordering::SeqCst) {
+                    continue;
+                }
+                // All chunks landed; verify the reassembled file if a digest
+                // was supplied.
+                if let Some(ref expected) = item.checksum {
+                    if let Err(err) = verify_file(path, expected) {
+                        let _ = fs::remove_file(path);
+                        message_queue.push(Message::Error { thread_id: key, err: err });
+                        continue;
+                    }
+                }
+                message_queue.push(Message::Success { thread_id: key });
+            }
+            _ => {
+                // Range requests aren't usable; download the whole file in one
+                // stream, retrying transient failures like the normal path.
+                let limiter = limiter.as_ref().map(|l| &**l);
+                let mut attempt = 0;
+                loop {
+                    match download_one(&item, resume, extract, false, timeout, limiter, message_queue) {
+                        Ok(_) => {
+                            message_queue.push(Message::Success { thread_id: thread_id::get() });
+                            break;
+                        }
+                        Err(err) => {
+                            if attempt < max_retries && is_transient(&err) {
+                                let delay = backoff_delay(attempt);
+                                message_queue.push(Message::Retrying {
+                                    thread_id: thread_id::get(),
+                                    attempt: attempt + 1,
+                                    delay: delay,
+                                });
+                                thread::sleep(delay);
+                                attempt += 1;
+                            } else {
+                                message_queue.push(Message::Error { thread_id: thread_id::get(), err: err });
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn download_in_parallel<U, P>(urls: Vec<U>, paths: &[P], checksums: &[Option<Vec<u8>>], thread_count: u32, timeout: u64, max_per_host: usize, max_retries: u32, resume: bool, split: bool, extract: bool, to_memory: bool, limiter: Option<Arc<RateLimiter>>, quiet: bool) -> DResult<Vec<(WorkItem, Vec<u8>)>>
     where U: IntoUrl,
           P: AsRef<Path>
 {
     if urls.len() != paths.len() {
         panic!("Not enough paths for URLs")
     }
+    if split && to_memory {
+        panic!("--split cannot be combined with --to-memory")
+    }
 
     let file_count = urls.len();
-    let workitem_queue = MsQueue::new();
+    let mut workitem_queue = VecDeque::new();
     for (i, (url, path)) in urls.into_iter().zip(paths.into_iter()).enumerate() {
         let path = path.as_ref();
         let workitem = WorkItem {
             path: path.to_path_buf(),
             url: url.into_url().unwrap(),
             id: i as u32,
+            checksum: checksums.get(i).and_then(|c| c.clone()),
         };
-        workitem_queue.push(workitem);
+        workitem_queue.push_back(workitem);
     }
 
-    let mut pool = Pool::new(thread_count);
     // let client = Arc::new(Client::new());
 
     let message_queue = Arc::new(MsQueue::new());
-    pool.scoped(|scope| {
-        while let Some(item) = workitem_queue.try_pop() {
-            let message_queue = message_queue.clone();
-            scope.execute(move || {
-                let mut client = Client::new();
-                client.set_read_timeout(Some(Duration::from_secs(timeout)));
-                let request = try_or_send!(client.get(item.url).send(), message_queue);
-                let length = request.headers.get::<ContentLength>().map(|c| c.0);
-                let path = item.path;
-                let mut writer = try_or_send!(File::create(path.clone()), message_queue);
-                let file_name: String = path.file_name().unwrap().to_str().unwrap().into();
+    let host_counts = Arc::new(Mutex::new(HashMap::new()));
+    let results = Arc::new(Mutex::new(Vec::new()));
 
-                message_queue.push(Message::StartFile {
-                    thread_id: thread_id::get(),
-                    file_name: file_name,
-                    file_size: length,
-                });
-                try_or_send!(io::copy(&mut request.watch(|n| {
-                                          message_queue.push(Message::Downloading {
-                                              bytes_read: n as u64,
-                                              thread_id: thread_id::get(),
-                                          })
-                                      }),
-                                      &mut writer),
-                             message_queue);
-
-                message_queue.push(Message::Success { thread_id: thread_id::get() });
-            });
-        }
+    // Progress watcher thread
+    if !quiet {
         let message_queue = message_queue.clone();
-        // Progress watcher thread
-        if !quiet {
-            thread::spawn(move || {
-                let mut download_watcher = DownloadWatcher::new(file_count);
-                let dt = Duration::from_millis(25);
-                let mut last = Instant::now();
-                loop {
-                    let msg = message_queue.pop();
-                    if download_watcher.process(msg) {
+        thread::spawn(move || {
+            let mut download_watcher = DownloadWatcher::new(file_count, max_retries);
+            let dt = Duration::from_millis(25);
+            let mut last = Instant::now();
+            loop {
+                let msg = message_queue.pop();
+                if download_watcher.process(msg) {
+                    break;
+                }
+                if last.elapsed() > dt {
+                    download_watcher.output();
+                    last = Instant::now();
+                }
+            }
+        });
+    }
+
+    if split {
+        download_split(workitem_queue, thread_count, timeout, max_per_host, max_retries, resume, extract, limiter, &message_queue);
+        message_queue.push(Message::Done);
+        return Ok(Vec::new());
+    }
+
+    let mut pool = Pool::new(thread_count);
+    pool.scoped(|scope| {
+        // Dispatch work items, but never let more than `max_per_host` downloads
+        // target the same host at once. When every remaining item points at a
+        // saturated host we briefly wait for an in-flight download to finish
+        // and free up a slot, leaving the other workers busy in the meantime.
+        while !workitem_queue.is_empty() {
+            let mut dispatched = None;
+            for _ in 0..workitem_queue.len() {
+                let item = workitem_queue.pop_front().unwrap();
+                let host = item.url.host_str().unwrap_or("").to_owned();
+                {
+                    let mut counts = host_counts.lock().unwrap();
+                    let count = counts.entry(host.clone()).or_insert(0);
+                    if *count < max_per_host {
+                        *count += 1;
+                        dispatched = Some((item, host));
                         break;
                     }
-                    if last.elapsed() > dt {
-                        download_watcher.output();
-                        last = Instant::now();
+                }
+                workitem_queue.push_back(item);
+            }
+
+            let (item, host) = match dispatched {
+                Some(pair) => pair,
+                None => {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+            };
+
+            let message_queue = message_queue.clone();
+            let host_counts = host_counts.clone();
+            let limiter = limiter.clone();
+            let results = results.clone();
+            scope.execute(move || {
+                let _host_guard = HostGuard { counts: host_counts, host: host };
+                let limiter = limiter.as_ref().map(|l| &**l);
+                // Retry transient failures with exponential backoff, resuming
+                // from whatever bytes already landed on disk on each attempt.
+                let mut attempt = 0;
+                loop {
+                    match download_one(&item, resume, extract, to_memory, timeout, limiter, &message_queue) {
+                        Ok(buffer) => {
+                            if let Some(bytes) = buffer {
+                                results.lock().unwrap().push((item, bytes));
+                            }
+                            message_queue.push(Message::Success { thread_id: thread_id::get() });
+                            break;
+                        }
+                        Err(err) => {
+                            if attempt < max_retries && is_transient(&err) {
+                                let delay = backoff_delay(attempt);
+                                message_queue.push(Message::Retrying {
+                                    thread_id: thread_id::get(),
+                                    attempt: attempt + 1,
+                                    delay: delay,
+                                });
+                                thread::sleep(delay);
+                                attempt += 1;
+                            } else {
+                                message_queue.push(Message::Error {
+                                    thread_id: thread_id::get(),
+                                    err: err,
+                                });
+                                break;
+                            }
+                        }
                     }
                 }
             });
@@ -358,24 +957,79 @@ pub fn download_in_parallel<U, P>(urls: Vec<U>, paths: &[P], thread_count: u32,
     });
     message_queue.push(Message::Done);
 
-    Ok(())
+    // Every worker has finished (the scope joined them), so we're the sole
+    // owner of the collected buffers.
+    let results = Arc::try_unwrap(results).unwrap().into_inner().unwrap();
+    Ok(results)
+}
+
+/// Parses a byte count that may carry a `k`/`m`/`g` (binary) suffix, e.g.
+/// `2M` for two mebibytes per second.
+fn parse_size(s: &str) -> u64 {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1024),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    number.trim().parse::<u64>().unwrap() * multiplier
+}
+
+/// Renders a byte slice as a lowercase hex string.
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// Decodes a lowercase/uppercase hex string into bytes, or `None` if it isn't
+/// valid hex.
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let chars: Vec<char> = s.chars().collect();
+    if chars.len() % 2 != 0 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    for pair in chars.chunks(2) {
+        match (pair[0].to_digit(16), pair[1].to_digit(16)) {
+            (Some(hi), Some(lo)) => bytes.push((hi * 16 + lo) as u8),
+            _ => return None,
+        }
+    }
+    Some(bytes)
 }
 
-fn read_urls(path: &str) -> (Vec<Url>, Vec<String>) {
+fn read_urls(path: &str) -> (Vec<Url>, Vec<String>, Vec<Option<Vec<u8>>>) {
     let mut urls = vec![];
     let mut paths = vec![];
+    let mut checksums = vec![];
 
     let reader = io::BufReader::new(File::open(path).unwrap());
 
     for line in reader.lines() {
-        let url = Url::parse(line.unwrap().trim()).unwrap();
+        let line = line.unwrap();
+        // Each line is `URL` optionally followed by `sha256:HEX`.
+        let mut fields = line.split_whitespace();
+        let url = Url::parse(fields.next().unwrap().trim()).unwrap();
+        let checksum = fields.next().and_then(|f| {
+            let f = f.trim();
+            if f.starts_with("sha256:") {
+                parse_hex(&f[7..])
+            } else {
+                None
+            }
+        });
         let k = url.path().rfind('/').unwrap();
         let name = &url.path()[k + 1..];
         paths.push(format!("downloads/{}", name));
         urls.push(url.clone());
+        checksums.push(checksum);
     }
 
-    (urls, paths)
+    (urls, paths, checksums)
 }
 
 fn main() {
@@ -398,6 +1052,30 @@ fn main() {
              .long("timeout")
              .help("HTTP timeout per thread in seconds.")
              .takes_value(true))
+        .arg(Arg::with_name("per-host")
+             .long("per-host")
+             .help("Maximum simultaneous downloads per host.")
+             .takes_value(true))
+        .arg(Arg::with_name("retries")
+             .long("retries")
+             .help("Number of times to retry a failed download.")
+             .takes_value(true))
+        .arg(Arg::with_name("no-resume")
+             .long("no-resume")
+             .help("Always download from scratch, ignoring partial files."))
+        .arg(Arg::with_name("split")
+             .long("split")
+             .help("Fetch each file with multiple threads using byte ranges."))
+        .arg(Arg::with_name("speed-limit")
+             .long("speed-limit")
+             .help("Cap aggregate download speed, e.g. 2M bytes/sec.")
+             .takes_value(true))
+        .arg(Arg::with_name("extract")
+             .long("extract")
+             .help("Unpack .tar.gz/.tar.bz2/.tar.xz archives while downloading."))
+        .arg(Arg::with_name("to-memory")
+             .long("to-memory")
+             .help("Collect downloads in memory instead of writing to disk."))
         .arg(Arg::with_name("quiet")
              .short("q")
              .long("quiet")
@@ -405,12 +1083,28 @@ fn main() {
         .get_matches();
 
     let filepath = matches.value_of("file").unwrap();
-    let (urls, paths) = read_urls(filepath);
+    let (urls, paths, checksums) = read_urls(filepath);
     let thread_count = matches.value_of("threads").map_or(4, |s| s.parse::<u32>().unwrap());
     let timeout = matches.value_of("timeout").map_or(15, |s| s.parse::<u64>().unwrap());
+    // `0` means "no cap"; otherwise it would make the dispatch loop spin forever
+    // because no host count can ever be below the limit.
+    let max_per_host = match matches.value_of("per-host").map(|s| s.parse::<usize>().unwrap()) {
+        None | Some(0) => usize::max_value(),
+        Some(n) => n,
+    };
+    let max_retries = matches.value_of("retries").map_or(3, |s| s.parse::<u32>().unwrap());
+    let resume = !matches.is_present("no-resume");
+    let split = matches.is_present("split");
+    let extract = matches.is_present("extract");
+    let to_memory = matches.is_present("to-memory");
+    let limiter = matches.value_of("speed-limit")
+        .map(|s| Arc::new(RateLimiter::new(parse_size(s))));
     let quiet = matches.is_present("quiet");
     let start = Instant::now();
-    download_in_parallel(urls, &paths, thread_count, timeout, quiet).unwrap();
+    let buffers = download_in_parallel(urls, &paths, &checksums, thread_count, timeout, max_per_host, max_retries, resume, split, extract, to_memory, limiter, quiet).unwrap();
+    if to_memory {
+        println!("Fetched {} files into memory", buffers.len());
+    }
     let elapsed = start.elapsed();
     println!("#Threads: {}, Duration: {} seconds", thread_count, elapsed.seconds());
 }